@@ -4,6 +4,8 @@ extern crate r2d2;
 extern crate postgres;
 
 use std::cell::RefCell;
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::collections::LruCache;
 use std::default::Default;
 use std::fmt;
@@ -16,8 +18,10 @@ use postgres::{PostgresConnection,
                PostgresResult,
                PostgresStatement,
                PostgresCopyInStatement,
-               PostgresTransaction};
-use postgres::error::{PostgresConnectError, PostgresError};
+               PostgresTransaction,
+               PostgresNotificationIterator};
+use postgres::error::{PostgresConnectError, PostgresError, PostgresSqlState};
+pub use postgres::PostgresCancelData;
 use postgres::types::ToSql;
 
 pub enum Error {
@@ -34,9 +38,95 @@ impl fmt::Show for Error {
     }
 }
 
+impl Error {
+    /// Returns the `SQLSTATE` code the server reported for this error, if
+    /// any. A `ConnectError` never reached a point where the server could
+    /// report a code. Nor does every `OtherError`: only the `DbError`
+    /// variant of `PostgresError` carries one, since it's the one built
+    /// from an `ErrorResponse` the server actually sent back — an I/O
+    /// failure or the like never reaches the server at all.
+    pub fn code(&self) -> Option<PostgresSqlState> {
+        match *self {
+            ConnectError(_) => None,
+            OtherError(PostgresError::DbError(ref e)) => Some(e.code.clone()),
+            OtherError(_) => None,
+        }
+    }
+
+    /// A `UNIQUE` constraint was violated (SQLSTATE `23505`).
+    pub fn is_unique_violation(&self) -> bool {
+        self.code() == Some(PostgresSqlState::UniqueViolation)
+    }
+
+    /// The transaction could not be committed because of a serialization
+    /// failure (SQLSTATE `40001`). Safe to retry the whole transaction
+    /// from the start.
+    pub fn is_serialization_failure(&self) -> bool {
+        self.code() == Some(PostgresSqlState::SerializationFailure)
+    }
+
+    /// The server reported that the connection itself is no longer usable
+    /// (a class `08`, "Connection Exception", SQLSTATE). Unlike
+    /// `PostgresPoolManager::is_valid`, which evicts on *any* error from
+    /// its no-op ping, this only covers errors the server itself
+    /// classified as connection-fatal; ordinary constraint violations and
+    /// the like are recoverable and return `false` here.
+    pub fn is_connection_exception(&self) -> bool {
+        match self.code() {
+            Some(PostgresSqlState::ConnectionException) |
+            Some(PostgresSqlState::ConnectionDoesNotExist) |
+            Some(PostgresSqlState::ConnectionFailure) |
+            Some(PostgresSqlState::SqlclientUnableToEstablishSqlconnection) |
+            Some(PostgresSqlState::SqlserverRejectedEstablishmentOfSqlconnection) |
+            Some(PostgresSqlState::TransactionResolutionUnknown) |
+            Some(PostgresSqlState::ProtocolViolation) => true,
+            _ => false,
+        }
+    }
+}
+
+/// A standalone, `Send` and `Clone` token that can cancel a query running
+/// on the `Connection` it was taken from.
+///
+/// `Connection` itself is not `Send` (it holds `Rc`/`RefCell`), so this
+/// token holds its own clone of the `PostgresConnectParams` and `SslMode`
+/// used to establish the connection, plus the `PostgresCancelData`
+/// captured when it connected, rather than borrowing the connection.
+#[deriving(Clone)]
+pub struct CancelToken {
+    params: PostgresConnectParams,
+    ssl_mode: SslMode,
+    data: PostgresCancelData,
+}
+
+impl CancelToken {
+    /// Opens a fresh connection to the server and asks it to cancel
+    /// whatever query is currently running on the connection this token
+    /// was taken from.
+    pub fn cancel_query(&self) -> Result<(), PostgresConnectError> {
+        cancel_query(self.params.clone(), &self.ssl_mode, self.data.clone())
+    }
+}
+
+/// Asks the server to cancel whatever query is running on the backend
+/// identified by `data`, by opening a fresh connection to `params` and
+/// sending a `CancelRequest`.
+///
+/// This does not use the connection pool: per the wire protocol, a
+/// cancellation must come in over its own new connection so it can be
+/// processed out-of-band while the original connection is busy running
+/// the query to be cancelled. Like any other fresh connect, failure comes
+/// back as a `PostgresConnectError`, not a `PostgresError` — no session
+/// has been established yet for the server to report a `SQLSTATE` against.
+pub fn cancel_query<T: IntoConnectParams>(params: T, ssl_mode: &SslMode, data: PostgresCancelData)
+        -> Result<(), PostgresConnectError> {
+    postgres::cancel_query(params, ssl_mode, data)
+}
+
 pub struct PostgresPoolManager {
     params: Result<PostgresConnectParams, PostgresConnectError>,
     ssl_mode: SslMode,
+    on_acquire: Option<Box<Fn(&PostgresConnection) -> PostgresResult<()> + Send + Sync>>,
 }
 
 impl PostgresPoolManager {
@@ -44,24 +134,49 @@ impl PostgresPoolManager {
         PostgresPoolManager {
             params: params.into_connect_params(),
             ssl_mode: ssl_mode,
+            on_acquire: None,
         }
     }
 }
 
 impl r2d2::PoolManager<PostgresConnection, Error> for PostgresPoolManager {
     fn connect(&self) -> Result<PostgresConnection, Error> {
-        match self.params {
+        let conn = match self.params {
             Ok(ref p) => {
-                PostgresConnection::connect(p.clone(), &self.ssl_mode).map_err(ConnectError)
+                try!(PostgresConnection::connect(p.clone(), &self.ssl_mode).map_err(ConnectError))
             }
-            Err(ref e) => Err(ConnectError(e.clone()))
+            Err(ref e) => return Err(ConnectError(e.clone())),
+        };
+
+        if let Some(ref on_acquire) = self.on_acquire {
+            try!((*on_acquire)(&conn).map_err(OtherError));
         }
+
+        Ok(conn)
     }
 
     fn is_valid(&self, conn: &mut PostgresConnection) -> Result<(), Error> {
+        // An empty `batch_execute` is just a round-trip ping; it neither
+        // reads nor discards any `NotificationResponse` messages queued up
+        // by a prior `LISTEN`, so a connection subscribed to channels is
+        // still reported valid and goes back into the pool with its
+        // subscriptions (and any buffered notifications) intact.
+        //
+        // A no-op statement has nothing to fail on except the connection
+        // itself (there's no constraint to violate, no row to conflict
+        // on), so any error here, classified or not (an I/O failure has no
+        // SQLSTATE at all), means the connection is broken and must be
+        // evicted. `Error::is_connection_exception` exists for callers
+        // classifying errors from real queries, not for this ping.
         conn.batch_execute("").map_err(OtherError)
     }
 
+    // `has_broken` is only handed the connection, not an error, so it can't
+    // inspect a SQLSTATE the way `Error::is_connection_exception` does;
+    // `is_desynchronized` is the one broken-ness signal available to it.
+    // Eviction on a connection-class (`08xxx`) error happens in `is_valid`
+    // above instead, which evicts on any ping failure, connection-class or
+    // not.
     fn has_broken(&self, conn: &mut PostgresConnection) -> bool {
         conn.is_desynchronized()
     }
@@ -69,36 +184,77 @@ impl r2d2::PoolManager<PostgresConnection, Error> for PostgresPoolManager {
 
 pub struct Config {
     pub statement_pool_size: uint,
+
+    /// Run on every freshly established connection before it enters the
+    /// pool, e.g. to `SET search_path`, `SET statement_timeout`, `SET TIME
+    /// ZONE`, or register prepared statements. An error is treated the
+    /// same as a failed connect: r2d2 discards the connection and tries
+    /// again rather than handing out a half-initialized one. Must be
+    /// `Send + Sync`, since r2d2 shares the manager holding it across the
+    /// pool's worker threads.
+    pub on_acquire: Option<Box<Fn(&PostgresConnection) -> PostgresResult<()> + Send + Sync>>,
+
+    /// Queries that, once prepared, are pinned in each connection's
+    /// statement cache and never evicted by LRU churn, so hot prepared
+    /// statements stay resident under a bursty workload that would
+    /// otherwise thrash a small `statement_pool_size`. Pinning is in
+    /// addition to, not instead of, the plain LRU cache used for every
+    /// other query.
+    pub pinned_queries: Vec<String>,
 }
 
 impl Default for Config {
     fn default() -> Config {
         Config {
             statement_pool_size: 10,
+            on_acquire: None,
+            pinned_queries: Vec::new(),
         }
     }
 }
 
 pub struct StatementPoolingManager {
     manager: PostgresPoolManager,
-    config: Config,
+    statement_pool_size: uint,
+    pinned_queries: Rc<HashSet<String>>,
 }
 
 impl StatementPoolingManager {
     pub fn new<T>(params: T, ssl_mode: SslMode, config: Config) -> StatementPoolingManager
             where T: IntoConnectParams {
         StatementPoolingManager {
-            manager: PostgresPoolManager::new(params, ssl_mode),
-            config: config
+            manager: PostgresPoolManager {
+                params: params.into_connect_params(),
+                ssl_mode: ssl_mode,
+                on_acquire: config.on_acquire,
+            },
+            statement_pool_size: config.statement_pool_size,
+            pinned_queries: Rc::new(config.pinned_queries.into_iter().collect()),
         }
     }
 }
 
 impl r2d2::PoolManager<Connection, Error> for StatementPoolingManager {
     fn connect(&self) -> Result<Connection, Error> {
+        // `self.manager.connect()` already runs the `on_acquire` hook (if
+        // any) against the raw connection before it comes back here.
+        let conn = try!(self.manager.connect());
+
+        let params = match self.manager.params {
+            Ok(ref p) => p.clone(),
+            Err(ref e) => return Err(ConnectError(e.clone())),
+        };
+        let cancel_token = CancelToken {
+            params: params,
+            ssl_mode: self.manager.ssl_mode.clone(),
+            data: conn.cancel_data(),
+        };
+
         Ok(Connection {
-            conn: box try!(self.manager.connect()),
-            stmts: RefCell::new(LruCache::new(self.config.statement_pool_size))
+            conn: box conn,
+            stmts: RefCell::new(StatementCache::new(self.statement_pool_size,
+                                                     self.pinned_queries.clone())),
+            cancel_token: cancel_token,
         })
     }
 
@@ -129,18 +285,119 @@ pub trait GenericConnection {
 
     /// Like `PostgresConnection::batch_execute`.
     fn batch_execute(&self, query: &str) -> PostgresResult<()>;
+
+    /// Like `PostgresConnection::notifications`.
+    ///
+    /// Returns an iterator draining any asynchronous `NotificationResponse`
+    /// messages the backend has sent since the last drain, for channels
+    /// subscribed to via `batch_execute("LISTEN ...")`. Each item is a
+    /// `PostgresNotification` carrying the sending backend's process id,
+    /// the channel name and the payload string. Whether a call blocks
+    /// waiting for a notification to arrive or returns immediately once the
+    /// queue is empty is controlled by the iterator itself, not by this
+    /// method; see `PostgresNotificationIterator`.
+    fn notifications<'a>(&'a self) -> PostgresNotificationIterator<'a>;
+}
+
+/// A snapshot of a `Connection`'s statement cache counters, for sizing
+/// `Config::statement_pool_size` and for visibility into cache churn under
+/// bursty workloads.
+#[deriving(Clone)]
+pub struct StatementCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+/// The statement cache backing a `Connection`: a plain LRU cache sized by
+/// `Config::statement_pool_size`, plus an unbounded, never-evicted map for
+/// `Config::pinned_queries`, and running hit/miss/eviction counters for
+/// both.
+struct StatementCache {
+    pinned_queries: Rc<HashSet<String>>,
+    pinned: HashMap<String, Rc<PostgresStatement<'static>>>,
+    lru: LruCache<String, Rc<PostgresStatement<'static>>>,
+    stats: StatementCacheStats,
+}
+
+impl StatementCache {
+    fn new(capacity: uint, pinned_queries: Rc<HashSet<String>>) -> StatementCache {
+        StatementCache {
+            pinned_queries: pinned_queries,
+            pinned: HashMap::new(),
+            lru: LruCache::new(capacity),
+            stats: StatementCacheStats { hits: 0, misses: 0, evictions: 0 },
+        }
+    }
+
+    fn get(&mut self, query: &str) -> Option<Rc<PostgresStatement<'static>>> {
+        if let Some(stmt) = self.pinned.get(query) {
+            self.stats.hits += 1;
+            return Some(stmt.clone());
+        }
+
+        match self.lru.get(query) {
+            Some(stmt) => {
+                self.stats.hits += 1;
+                Some(stmt.clone())
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn put(&mut self, query: String, stmt: Rc<PostgresStatement<'static>>) {
+        if self.pinned_queries.contains(&query) {
+            self.pinned.insert(query, stmt);
+            return;
+        }
+
+        // `LruCache::put` silently DEALLOCATEs whatever it evicts to make
+        // room; this is the only place that can happen, so it's the only
+        // place we can count it.
+        if self.lru.len() == self.lru.capacity() && !self.lru.contains_key(&query) {
+            self.stats.evictions += 1;
+        }
+        self.lru.put(query, stmt);
+    }
+
+    fn stats(&self) -> StatementCacheStats {
+        self.stats.clone()
+    }
+
+    // Drop every cached statement before the connection they borrow from
+    // goes away.
+    fn drain(&mut self) {
+        self.lru.change_capacity(0);
+        self.pinned.clear();
+    }
 }
 
 pub struct Connection {
     conn: Box<PostgresConnection>,
-    stmts: RefCell<LruCache<String, Rc<PostgresStatement<'static>>>>,
+    stmts: RefCell<StatementCache>,
+    cancel_token: CancelToken,
+}
+
+impl Connection {
+    /// Returns a `Send`, `Clone` token that can be handed to another thread
+    /// to cancel a query running on this connection via `cancel_query`.
+    pub fn cancel_data(&self) -> CancelToken {
+        self.cancel_token.clone()
+    }
+
+    /// Returns this connection's statement cache hit/miss/eviction counts.
+    pub fn statement_stats(&self) -> StatementCacheStats {
+        self.stmts.borrow().stats()
+    }
 }
 
 #[unsafe_destructor]
 impl Drop for Connection {
-    // Just make sure that all the statements drop before the connection
     fn drop(&mut self) {
-        self.stmts.borrow_mut().change_capacity(0);
+        self.stmts.borrow_mut().drain();
     }
 }
 
@@ -150,7 +407,16 @@ impl GenericConnection for Connection {
         let mut stmts = self.stmts.borrow_mut();
 
         if let Some(stmt) = stmts.get(&query) {
-            return Ok(unsafe { mem::transmute(stmt.clone()) });
+            // SAFETY: `stmts` stores every statement under the erased
+            // `'static` lifetime so the cache can outlive any one borrow
+            // of `self`, but every entry was prepared right here, directly
+            // on `self.conn`, and `Connection`'s `Drop` empties the cache
+            // before `self.conn` itself is dropped — so shrinking the
+            // lifetime back down to `'a` is sound. Statements a
+            // `Transaction` prepares are never promoted into this cache
+            // (see `Transaction::prepare`), so that invariant can't be
+            // violated by a later rollback.
+            return Ok(unsafe { mem::transmute(stmt) });
         }
 
         let stmt = Rc::new(try!(self.conn.prepare(query[])));
@@ -166,30 +432,75 @@ impl GenericConnection for Connection {
     fn transaction<'a>(&'a self) -> PostgresResult<Transaction<'a>> {
         Ok(Transaction {
             conn: self,
-            trans: try!(self.conn.transaction())
+            trans: try!(self.conn.transaction()),
+            stmts: RefCell::new(HashMap::new()),
         })
     }
 
     fn batch_execute(&self, query: &str) -> PostgresResult<()> {
         self.conn.batch_execute(query)
     }
+
+    fn notifications<'a>(&'a self) -> PostgresNotificationIterator<'a> {
+        self.conn.notifications()
+    }
 }
 
 pub struct Transaction<'a> {
     conn: &'a Connection,
-    trans: PostgresTransaction<'a>
+    trans: PostgresTransaction<'a>,
+    // Statements this transaction itself prepared (as opposed to ones
+    // already found in `conn.stmts`), keyed by query text. Scoped to this
+    // `Transaction`'s own lifetime, *not* promoted into the shared,
+    // checkout-wide `conn.stmts` cache — see the safety note in `prepare`
+    // for why.
+    //
+    // SAFETY: stored under the erased `'static` lifetime for the same
+    // reason as `Connection::stmts` — every entry was prepared right here,
+    // on `self.trans`, so shrinking the lifetime back down to `'a` on read
+    // is sound. `Transaction`'s `Drop` clears the map before `self.trans`
+    // itself is dropped, so no entry ever outlives the `self.trans` it
+    // borrows from.
+    stmts: RefCell<HashMap<String, Rc<PostgresStatement<'static>>>>,
+}
+
+#[unsafe_destructor]
+impl<'a> Drop for Transaction<'a> {
+    fn drop(&mut self) {
+        self.stmts.borrow_mut().clear();
+    }
 }
 
 impl<'a> GenericConnection for Transaction<'a> {
     fn prepare<'a>(&'a self, query: &str) -> PostgresResult<Rc<PostgresStatement<'a>>> {
         let query = query.into_string();
-        let mut stmts = self.conn.stmts.borrow_mut();
 
+        if let Some(stmt) = self.conn.stmts.borrow_mut().get(&query) {
+            // Entries in `conn.stmts` were all prepared directly on the
+            // connection by `Connection::prepare` (see the note there),
+            // never inside a transaction, so they're session-scoped and
+            // safe to reuse here regardless of whether this transaction
+            // later commits or rolls back.
+            return Ok(unsafe { mem::transmute(stmt) });
+        }
+
+        let mut stmts = self.stmts.borrow_mut();
         if let Some(stmt) = stmts.get(&query) {
+            // SAFETY: see the note on the `stmts` field.
             return Ok(unsafe { mem::transmute(stmt.clone()) });
         }
 
-        Ok(Rc::new(try!(self.trans.prepare(query[]))))
+        // A statement this transaction prepares is deallocated by the
+        // server if the transaction rolls back (or errors out). Caching it
+        // only in `self.stmts`, which is dropped along with this
+        // `Transaction`, means a rollback can never leave a stale entry
+        // behind in the shared, checkout-wide `conn.stmts` cache — the
+        // bug that a prior version of this cache had. Repeated `prepare`
+        // calls for the same query still only parse on the server once,
+        // as long as they happen within this same still-open transaction.
+        let stmt = Rc::new(try!(self.trans.prepare(query[])));
+        stmts.insert(query, unsafe { mem::transmute(stmt.clone()) });
+        Ok(stmt)
     }
 
     fn prepare_copy_in<'a>(&'a self, table: &str, columns: &[&str])
@@ -200,11 +511,50 @@ impl<'a> GenericConnection for Transaction<'a> {
     fn transaction<'a>(&'a self) -> PostgresResult<Transaction<'a>> {
         Ok(Transaction {
             conn: self.conn,
-            trans: try!(self.trans.transaction())
+            trans: try!(self.trans.transaction()),
+            stmts: RefCell::new(HashMap::new()),
         })
     }
 
     fn batch_execute(&self, query: &str) -> PostgresResult<()> {
         self.trans.batch_execute(query)
     }
+
+    fn notifications<'a>(&'a self) -> PostgresNotificationIterator<'a> {
+        // Notifications belong to the session, not any one transaction, so
+        // route through the owning `Connection` rather than `self.trans`.
+        self.conn.notifications()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    // Requires a local server reachable at this URL, matching the
+    // convention of the underlying `postgres` driver's own test suite.
+    use std::default::Default;
+    use r2d2::PoolManager;
+    use postgres::SslMode;
+    use {StatementPoolingManager, GenericConnection};
+
+    fn test_manager() -> StatementPoolingManager {
+        StatementPoolingManager::new("postgres://postgres@localhost/postgres",
+                                      SslMode::None,
+                                      Default::default())
+    }
+
+    #[test]
+    fn transaction_prepare_reuses_cached_statement() {
+        let manager = test_manager();
+        let conn = manager.connect().ok().expect("failed to connect");
+        let trans = conn.transaction().ok().expect("failed to start transaction");
+
+        let first = trans.prepare("SELECT 1").ok().expect("failed to prepare");
+        let second = trans.prepare("SELECT 1").ok().expect("failed to prepare");
+
+        // Had the second `prepare` call re-parsed the query on the server
+        // instead of hitting the transaction's statement cache, it would
+        // come back as a distinct `PostgresStatement`. Identical addresses
+        // prove only one server-side parse happened for the two calls.
+        assert_eq!(&*first as *const _, &*second as *const _);
+    }
 }